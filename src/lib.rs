@@ -1,4 +1,7 @@
+mod persistence;
+mod session;
 mod util;
+mod worker;
 
 // rsheet_lib imports
 use rsheet_lib::command::{Command, CellIdentifier};
@@ -7,49 +10,92 @@ use rsheet_lib::replies::Reply;
 use rsheet_lib::cell_expr::{CellExpr, CellArgument};
 use rsheet_lib::cell_value::CellValue;
 
+// Third-party imports
+use rusqlite::Connection as DbConnection;
+
 // Standard lib imports
+use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::Instant;
-use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+use std::collections::HashMap;
 use std::error::Error;
 use std::io::{self, Write};
 
 // Internal imports
+use crate::session::SessionTable;
 use crate::util::cell_id_to_string;
+use crate::worker::WorkerPool;
+
+/// Number of background threads recalculating dependency cascades.
+const RECALC_WORKERS: usize = 4;
+
+/// How long a session may go without a message before it's dropped and its
+/// buffered replies discarded.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// How often the idle-session sweep runs.
+const SESSION_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
 
 // Cells
 #[derive(Debug, Clone)]
-struct TimedCellValue {
+pub(crate) struct TimedCellValue {
     value: CellValue,
     expression: Option<String>,
     timestamp: Instant,
 }
-type CellGrid = HashMap<String, TimedCellValue>;
+pub(crate) type CellGrid = HashMap<String, TimedCellValue>;
 
 // Dependencies
-type DependencyGraph = HashMap<String, Vec<String>>;
+pub(crate) type DependencyGraph = HashMap<String, Vec<String>>;
 
 // Spreadsheet
-#[derive(Debug, Clone)]
-struct Spreadsheet {
+#[derive(Debug)]
+pub(crate) struct Spreadsheet {
     cells: CellGrid,
     dependencies: DependencyGraph,
+    db: DbConnection,
 }
-type SharedSpreadsheet = Arc<Mutex< Spreadsheet >>;
+pub(crate) type SharedSpreadsheet = Arc<Mutex< Spreadsheet >>;
 
 // Thread handles
 type ThreadHandles = Arc<Mutex< Vec<JoinHandle<()> >>>;
 
-pub fn start_server<M>(mut manager: M) -> Result<(), Box<dyn Error>>
+pub fn start_server<M>(mut manager: M, db_path: Option<String>) -> Result<(), Box<dyn Error>>
 where
     M: Manager,
 {
+    // No `--db` means an ephemeral, in-memory database: same behaviour as
+    // before persistence existed, just routed through the same code path.
+    let db_path = db_path.unwrap_or_else(|| ":memory:".to_string());
+    let db = persistence::open_db(&db_path)?;
+    let (cells, dependencies) = persistence::load_spreadsheet(&db)?;
+
     let spreadsheet = Arc::new(Mutex::new(Spreadsheet {
-        cells: HashMap::new(),
-        dependencies: HashMap::new(),
+        cells,
+        dependencies,
+        db,
     }));
 
+    // Recalculation status updates ("recomputing B7") are just logged for
+    // now; this gives external tooling a single place to hook in later.
+    // Routed to stderr, not stdout: in terminal mode stdout is the client
+    // reply stream, and an interleaved status line would corrupt it.
+    let (status_tx, status_rx) = mpsc::channel::<String>();
+    thread::spawn(move || {
+        for status in status_rx {
+            eprintln!("{}", status);
+        }
+    });
+    let worker_pool = Arc::new(WorkerPool::new(RECALC_WORKERS, Arc::clone(&spreadsheet), status_tx));
+
+    let sessions = Arc::new(SessionTable::new(SESSION_IDLE_TIMEOUT));
+    let sweep_sessions = Arc::clone(&sessions);
+    thread::spawn(move || loop {
+        thread::sleep(SESSION_SWEEP_INTERVAL);
+        sweep_sessions.sweep_expired();
+    });
+
     let thread_handles = Arc::new(Mutex::new(Vec::new()));
 
     loop {
@@ -58,8 +104,10 @@ where
             Connection::NewConnection { reader, writer } => {
                 let spreadsheet = Arc::clone(&spreadsheet);
                 let thread_handles_clone = Arc::clone(&thread_handles);
+                let worker_pool = Arc::clone(&worker_pool);
+                let sessions = Arc::clone(&sessions);
 
-                let handle = thread::spawn(move || handle_client(reader, writer, spreadsheet, thread_handles_clone));
+                let handle = thread::spawn(move || handle_client(reader, writer, spreadsheet, thread_handles_clone, worker_pool, sessions));
                 thread_handles.lock().unwrap().push(handle);
             },
             Connection::NoMoreConnections => {
@@ -70,26 +118,70 @@ where
     }
 }
 
-fn handle_client(mut reader: impl Reader, mut writer: impl Writer, spreadsheet: SharedSpreadsheet, thread_handles: ThreadHandles) {
+fn handle_client(mut reader: impl Reader, mut writer: impl Writer, spreadsheet: SharedSpreadsheet, thread_handles: ThreadHandles, worker_pool: Arc<WorkerPool>, sessions: Arc<SessionTable>) {
+    // A connection may be tagged with a session id via a leading `session` /
+    // `session <id>` handshake message; once tagged, replies this thread
+    // fails to deliver are buffered in `sessions` instead of lost, so a
+    // later reconnect carrying the same id can pick them up.
+    let mut session_id: Option<String> = None;
+
     loop {
         // Read request message from client
         let message: ReadMessageResult = reader.read_message();
 
         match message {
             ReadMessageResult::Message(msg) => {
+                if session_id.is_none() {
+                    if let Some((id, newly_issued)) = session::parse_handshake(&msg) {
+                        let mut buffered = sessions.attach(&id).into_iter();
+
+                        if newly_issued {
+                            let _ = writer.write_message(session::issued_session_reply(&id));
+                        }
+
+                        // If the flush itself hits a flaky socket, the
+                        // reply that failed and everything still queued
+                        // behind it must go back into the buffer rather
+                        // than being dropped, or a client that reconnects
+                        // onto a still-bad connection loses them for good.
+                        while let Some(reply) = buffered.next() {
+                            if matches!(writer.write_message(reply.clone()), WriteMessageResult::ConnectionClosed | WriteMessageResult::Err(_)) {
+                                sessions.buffer(&id, reply);
+                                for remaining in buffered {
+                                    sessions.buffer(&id, remaining);
+                                }
+                                break;
+                            }
+                        }
+
+                        session_id = Some(id);
+                        continue;
+                    }
+                }
+
                 // Handle command and get reply
-                let reply = handle_command(msg, &spreadsheet, thread_handles.clone());
+                let reply = handle_command(msg, &spreadsheet, &worker_pool);
 
                 // Write reply message to client
-                match writer.write_message(reply) {
+                match writer.write_message(reply.clone()) {
                     WriteMessageResult::Ok => { // Message sent successfully
+                        if let Some(id) = &session_id {
+                            sessions.touch(id);
+                        }
                         if let Err(e) = io::stdout().flush() {
                             eprintln!("error flushing stdout: {}", e);
                         }
                         continue;
-                    } 
-                    WriteMessageResult::ConnectionClosed => break, // Connection closed, terminate
-                    WriteMessageResult::Err(_) => break, // Unexpected error occurred
+                    }
+                    WriteMessageResult::ConnectionClosed | WriteMessageResult::Err(_) => {
+                        // A transient disconnect shouldn't lose this reply:
+                        // buffer it for whichever connection reattaches to
+                        // this session next, if any.
+                        if let Some(id) = &session_id {
+                            sessions.buffer(id, reply);
+                        }
+                        break;
+                    }
                 }
             },
             ReadMessageResult::ConnectionClosed => break, // Connection closed, terminate
@@ -98,7 +190,7 @@ fn handle_client(mut reader: impl Reader, mut writer: impl Writer, spreadsheet:
     }
 }
 
-fn handle_command(command_str: String, spreadsheet: &SharedSpreadsheet, thread_handles: ThreadHandles) -> Reply {
+fn handle_command(command_str: String, spreadsheet: &SharedSpreadsheet, worker_pool: &Arc<WorkerPool>) -> Reply {
     let command: Command = match command_str.parse::<Command>() {
         Ok(command) => command,
         Err(e) => return Reply::Error(e.to_string()),
@@ -135,6 +227,12 @@ fn handle_command(command_str: String, spreadsheet: &SharedSpreadsheet, thread_h
                     Err(_) => return Reply::Error("could not evaluate expression".to_string()),
                 };
 
+                // Check for (and reject) circular dependencies before
+                // touching `cells`, so a rejected `Set` is a no-op.
+                if let Err(e) = update_dependencies(&mut spreadsheet.dependencies, &cell_id_str, &CellExpr::new(&cell_expr_str)) {
+                    return Reply::Error(e);
+                }
+
                 spreadsheet.cells.insert(
                     cell_id_str.clone(),
                     TimedCellValue {
@@ -144,110 +242,124 @@ fn handle_command(command_str: String, spreadsheet: &SharedSpreadsheet, thread_h
                     }
                 );
 
-                update_dependencies(&mut spreadsheet.dependencies, &cell_id_str, &CellExpr::new(&cell_expr_str));
+                persist_cell_and_deps(
+                    &mut spreadsheet.db,
+                    &cell_id_str,
+                    &cell_value,
+                    Some(&cell_expr_str),
+                    &cell_expr.find_variable_names(),
+                );
             }
 
-            trigger_updates(Arc::clone(spreadsheet), cell_id_str.clone(), thread_handles);
+            worker_pool.enqueue(spreadsheet, cell_id_str.clone());
 
             Reply::Value(cell_id_str, cell_value.clone())
         }
     }
 }
 
-/*
-fn detect_cycle(dependencies: &DependencyGraph, cell_id: &str) -> bool {
-    let mut visited = HashSet::new();
-    let mut stack = vec![cell_id.to_string()];
+/// Three-color (white/gray/black) DFS marking used by [`has_cycle_from`] to
+/// detect back-edges in a single linear pass.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Color {
+    White,
+    Gray,
+    Black,
+}
 
-    while let Some(current) = stack.pop() {
-        if visited.contains(&current) {
-            eprintln!("Cycle detected at cell: {}", current);
-            return true;
-        }
-        visited.insert(current.clone());
-        if let Some(dependents) = dependencies.get(&current) {
-            stack.extend(dependents.clone());
+/// Returns `true` if `graph` contains a cycle reachable from `start` by
+/// following dependency edges (`graph[x]` = cells that depend on `x`). The
+/// cycle need not pass through `start` itself; when the rest of `graph` is
+/// acyclic, as it is before each `Set`, any cycle found does pass through
+/// `start`. Nodes are marked gray while on
+/// the current DFS path and black once fully explored; revisiting a gray
+/// node is a back-edge, the signature of a cycle.
+///
+/// Uses an explicit stack rather than recursion: a long dependency chain
+/// (A1 -> A2 -> ... -> An) would otherwise recurse to depth n and could
+/// overflow the handling thread's stack.
+fn has_cycle_from(graph: &DependencyGraph, start: &str) -> bool {
+    let mut colors: HashMap<String, Color> = HashMap::new();
+
+    // Each stack frame is (node, next unvisited dependent index), so we can
+    // resume a node's iteration after recursing into one of its dependents.
+    let mut stack: Vec<(String, usize)> = vec![(start.to_string(), 0)];
+    colors.insert(start.to_string(), Color::Gray);
+
+    while let Some((node, next)) = stack.pop() {
+        let dependents = graph.get(&node);
+        let dependent = dependents.and_then(|deps| deps.get(next));
+
+        match dependent {
+            Some(dependent) => {
+                // Resume this node at the following dependent once its
+                // subtree (if any) has been explored.
+                stack.push((node, next + 1));
+
+                match colors.get(dependent).copied().unwrap_or(Color::White) {
+                    Color::Gray => return true,
+                    Color::Black => {}
+                    Color::White => {
+                        colors.insert(dependent.clone(), Color::Gray);
+                        stack.push((dependent.clone(), 0));
+                    }
+                }
+            }
+            None => {
+                colors.insert(node, Color::Black);
+            }
         }
     }
+
     false
 }
-*/
 
-fn update_dependencies(dependencies: &mut DependencyGraph, cell_id: &str, cell_expr: &CellExpr) {
+/// Rebuilds `cell_id`'s dependency edges from `cell_expr`, rejecting the
+/// change if it would close a cycle (e.g. `A1 = B1` then `B1 = A1`).
+///
+/// The new edge set is assembled on a scratch copy of `dependencies` first;
+/// `dependencies` itself is only overwritten once that copy is confirmed
+/// acyclic, so a rejected `Set` leaves it untouched.
+fn update_dependencies(dependencies: &mut DependencyGraph, cell_id: &str, cell_expr: &CellExpr) -> Result<(), String> {
     let vars = cell_expr.find_variable_names();
 
+    let mut tentative = dependencies.clone();
+
     // Remove `cell_id` from all current dependencies
-    for deps in dependencies.values_mut() {
+    for deps in tentative.values_mut() {
         deps.retain(|dep| dep != cell_id);
     }
 
     // Add `cell_id` as a dependent to all variables in `cell_expr`
-    for var in vars {
-        dependencies
-            .entry(var)
+    for var in &vars {
+        tentative
+            .entry(var.clone())
             .or_insert_with(Vec::new)
             .push(cell_id.to_string());
     }
 
-    /*
-    if detect_cycle(dependencies, cell_id) {
-        eprintln!("cycle detected after updating dependencies for cell: {}", cell_id);
-        dependencies.remove(cell_id);
+    if has_cycle_from(&tentative, cell_id) {
+        return Err("circular dependency detected".to_string());
     }
-    */
+
+    *dependencies = tentative;
+    Ok(())
 }
 
-fn trigger_updates(shared_spreadsheet: SharedSpreadsheet, updated_cell: String, thread_handles: ThreadHandles) {
-    let mut queue = vec![updated_cell];
-    let mut visited = HashSet::new();
+/// Write-through upsert of a cell's current value/expression into the
+/// database, so `cells` on disk never lags behind `cells` in memory.
+pub(crate) fn persist_cell(db: &DbConnection, cell_id: &str, value: &CellValue, expression: Option<&str>) {
+    if let Err(e) = persistence::upsert_cell(db, cell_id, value, expression, persistence::now_ms()) {
+        eprintln!("failed to persist cell {}: {}", cell_id, e);
+    }
+}
 
-    while let Some(cell) = queue.pop() {
-        if visited.contains(&cell) {
-            continue; // Avoid processing the same cell multiple times
-        }
-        visited.insert(cell.clone());
-
-        let mut spreadsheet = shared_spreadsheet.lock().unwrap();
-
-        if let Some(dependents) = spreadsheet.dependencies.get(&cell).cloned() {
-            for dependent in dependents {
-                if let Some(original_expr) = spreadsheet.cells.get(&dependent) {
-                    if let Some(original_expr_str) = &original_expr.expression {
-                        let cloned_expression = original_expr.expression.clone();
-
-                        let new_cell_expr = CellExpr::new(&original_expr_str);
-                        let context = handle_context(&new_cell_expr, &spreadsheet.cells);
-
-                        match new_cell_expr.evaluate(&context) {
-                            Ok(new_value) => {
-                                spreadsheet.cells.insert(
-                                    dependent.clone(),
-                                    TimedCellValue {
-                                        value: new_value,
-                                        expression: cloned_expression,
-                                        timestamp: Instant::now(),
-                                    },
-                                );
-
-                                queue.push(dependent.clone());
-                            }
-                            Err(_) => {
-                                spreadsheet.cells.insert(
-                                    dependent.clone(),
-                                    TimedCellValue {
-                                        value: CellValue::Error("evaluation failed".to_string()),
-                                        expression: cloned_expression,
-                                        timestamp: Instant::now(),
-                                    },
-                                );
-                            }
-                        }
-                    } else {
-                        println!("dependent {} has no valid expression to evaluate", dependent);
-                    }
-                }
-            }
-        }
+/// Write-through upsert of a cell's value/expression and its dependency
+/// edges together, in one transaction, so the two can never be observed out
+/// of sync on reload.
+fn persist_cell_and_deps(db: &mut DbConnection, cell_id: &str, value: &CellValue, expression: Option<&str>, depends_on: &[String]) {
+    if let Err(e) = persistence::write_through(db, cell_id, value, expression, depends_on, persistence::now_ms()) {
+        eprintln!("failed to persist cell {} and its dependencies: {}", cell_id, e);
     }
 }
 
@@ -258,7 +370,7 @@ fn wait_for_threads(thread_handles: ThreadHandles) {
     }
 }
 
-fn handle_context(cell_expr: &CellExpr, cells: &CellGrid) -> HashMap<String, CellArgument> {
+pub(crate) fn handle_context(cell_expr: &CellExpr, cells: &CellGrid) -> HashMap<String, CellArgument> {
     let mut context: HashMap<String, CellArgument> = HashMap::new();
     let variables = cell_expr.find_variable_names();
 
@@ -366,3 +478,35 @@ fn build_matrix(range: &[String], cells: &CellGrid) -> Vec<Vec<CellValue>> {
 
     matrix
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn graph(edges: &[(&str, &str)]) -> DependencyGraph {
+        let mut graph: DependencyGraph = HashMap::new();
+        for (var, dependent) in edges {
+            graph.entry(var.to_string()).or_insert_with(Vec::new).push(dependent.to_string());
+        }
+        graph
+    }
+
+    #[test]
+    fn diamond_is_acyclic() {
+        // A feeds B and C, both of which feed D: no back-edge anywhere.
+        let graph = graph(&[("a1", "b1"), ("a1", "c1"), ("b1", "d1"), ("c1", "d1")]);
+        assert!(!has_cycle_from(&graph, "a1"));
+    }
+
+    #[test]
+    fn direct_cycle_is_detected() {
+        let graph = graph(&[("a1", "b1"), ("b1", "a1")]);
+        assert!(has_cycle_from(&graph, "a1"));
+    }
+
+    #[test]
+    fn self_loop_is_detected() {
+        let graph = graph(&[("a1", "a1")]);
+        assert!(has_cycle_from(&graph, "a1"));
+    }
+}
@@ -12,6 +12,11 @@ struct Args {
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// Path to the SQLite database used to persist spreadsheet state.
+    /// Defaults to an ephemeral in-memory database when omitted.
+    #[arg(long)]
+    db: Option<String>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -22,9 +27,9 @@ fn main() -> Result<(), Box<dyn Error>> {
     if let Some(addr) = args.addr {
         let addr = resolve_address(&addr)?;
         let manager = ConnectionManager::launch(addr.ip(), addr.port());
-        start_server(manager)
+        start_server(manager, args.db)
     } else {
         let manager = TerminalManager::launch(args.mark_mode);
-        start_server(manager)
+        start_server(manager, args.db)
     }
 }
@@ -0,0 +1,195 @@
+// rsheet_lib imports
+use rsheet_lib::cell_value::CellValue;
+
+// Third-party imports
+use rusqlite::{params, Connection};
+
+// Standard lib imports
+use std::collections::HashMap;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+// Internal imports
+use crate::{CellGrid, DependencyGraph, TimedCellValue};
+
+/// Opens (creating if necessary) the SQLite database backing a `Spreadsheet`
+/// and ensures the `cells` and `deps` tables exist.
+pub fn open_db(path: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(path)?;
+
+    conn.execute_batch(
+        "CREATE TABLE IF NOT EXISTS cells (
+            id TEXT PRIMARY KEY,
+            value BLOB,
+            expression TEXT,
+            timestamp_ms INTEGER
+        );
+        CREATE TABLE IF NOT EXISTS deps (
+            cell TEXT,
+            depends_on TEXT
+        );",
+    )?;
+
+    Ok(conn)
+}
+
+/// Rehydrates a `CellGrid` and `DependencyGraph` from the rows previously
+/// written by [`upsert_cell`] and [`replace_deps`].
+pub fn load_spreadsheet(conn: &Connection) -> rusqlite::Result<(CellGrid, DependencyGraph)> {
+    let mut cells: CellGrid = HashMap::new();
+
+    let mut stmt = conn.prepare("SELECT id, value, expression FROM cells")?;
+    let rows = stmt.query_map([], |row| {
+        let id: String = row.get(0)?;
+        let value: Vec<u8> = row.get(1)?;
+        let expression: Option<String> = row.get(2)?;
+        Ok((id, value, expression))
+    })?;
+
+    for row in rows {
+        let (id, value, expression) = row?;
+
+        cells.insert(
+            id,
+            TimedCellValue {
+                value: decode_cell_value(&value),
+                expression,
+                // The on-disk timestamp is wall-clock (see `now_ms`); `Instant`
+                // has no epoch, so recovered cells are simply stamped "now".
+                timestamp: Instant::now(),
+            },
+        );
+    }
+
+    // `deps` rows are (dependent_cell, variable_it_depends_on), so the
+    // `DependencyGraph` (variable -> dependents) is built by flipping them.
+    let mut dependencies: DependencyGraph = HashMap::new();
+
+    let mut stmt = conn.prepare("SELECT cell, depends_on FROM deps")?;
+    let rows = stmt.query_map([], |row| {
+        let cell: String = row.get(0)?;
+        let depends_on: String = row.get(1)?;
+        Ok((cell, depends_on))
+    })?;
+
+    for row in rows {
+        let (cell, depends_on) = row?;
+        dependencies.entry(depends_on).or_insert_with(Vec::new).push(cell);
+    }
+
+    Ok((cells, dependencies))
+}
+
+/// Write-through upsert of a single cell's latest value and expression.
+pub fn upsert_cell(
+    conn: &Connection,
+    cell_id: &str,
+    value: &CellValue,
+    expression: Option<&str>,
+    timestamp_ms: i64,
+) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO cells (id, value, expression, timestamp_ms) VALUES (?1, ?2, ?3, ?4)
+         ON CONFLICT(id) DO UPDATE SET
+            value = excluded.value,
+            expression = excluded.expression,
+            timestamp_ms = excluded.timestamp_ms",
+        params![cell_id, encode_cell_value(value), expression, timestamp_ms],
+    )?;
+
+    Ok(())
+}
+
+/// Replaces the persisted dependency edges for `cell_id` with `depends_on`.
+pub fn replace_deps(conn: &Connection, cell_id: &str, depends_on: &[String]) -> rusqlite::Result<()> {
+    conn.execute("DELETE FROM deps WHERE cell = ?1", params![cell_id])?;
+
+    for var in depends_on {
+        conn.execute(
+            "INSERT INTO deps (cell, depends_on) VALUES (?1, ?2)",
+            params![cell_id, var],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Write-through upsert of a cell's value/expression together with its
+/// dependency edges, as a single transaction. Without this, a crash between
+/// the two writes could leave a cell's stored expression out of step with
+/// its stored `deps` rows, and `load_spreadsheet` does not check for that.
+pub fn write_through(
+    conn: &mut Connection,
+    cell_id: &str,
+    value: &CellValue,
+    expression: Option<&str>,
+    depends_on: &[String],
+    timestamp_ms: i64,
+) -> rusqlite::Result<()> {
+    let tx = conn.transaction()?;
+    upsert_cell(&tx, cell_id, value, expression, timestamp_ms)?;
+    replace_deps(&tx, cell_id, depends_on)?;
+    tx.commit()
+}
+
+/// Milliseconds since the Unix epoch, for the `timestamp_ms` column.
+pub fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0)
+}
+
+/// `CellValue` has no native SQLite representation, so it is tagged with a
+/// single-character prefix ("I" Int, "S" String, "N" None, "E" Error) and a
+/// ':' separator (e.g. "I:42") and stored as a UTF-8 blob.
+fn encode_cell_value(value: &CellValue) -> Vec<u8> {
+    match value {
+        CellValue::Int(i) => format!("I:{}", i).into_bytes(),
+        CellValue::String(s) => format!("S:{}", s).into_bytes(),
+        CellValue::None => b"N:".to_vec(),
+        CellValue::Error(e) => format!("E:{}", e).into_bytes(),
+    }
+}
+
+fn decode_cell_value(bytes: &[u8]) -> CellValue {
+    let encoded = String::from_utf8_lossy(bytes);
+    let (tag, rest) = encoded.split_once(':').unwrap_or(("N", ""));
+
+    match tag {
+        "I" => rest.parse::<i64>().map(CellValue::Int).unwrap_or(CellValue::None),
+        "S" => CellValue::String(rest.to_string()),
+        "E" => CellValue::Error(rest.to_string()),
+        _ => CellValue::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(value: CellValue) -> CellValue {
+        decode_cell_value(&encode_cell_value(&value))
+    }
+
+    #[test]
+    fn round_trips_int() {
+        assert_eq!(round_trip(CellValue::Int(42)), CellValue::Int(42));
+    }
+
+    #[test]
+    fn round_trips_string_containing_colon() {
+        let value = CellValue::String("a:b:c".to_string());
+        assert_eq!(round_trip(value.clone()), value);
+    }
+
+    #[test]
+    fn round_trips_none() {
+        assert_eq!(round_trip(CellValue::None), CellValue::None);
+    }
+
+    #[test]
+    fn round_trips_error() {
+        let value = CellValue::Error("div by zero".to_string());
+        assert_eq!(round_trip(value.clone()), value);
+    }
+}
@@ -0,0 +1,133 @@
+// rsheet_lib imports
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+
+// Standard lib imports
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub type SessionId = String;
+
+/// Server-side state for one logical client session, kept alive across a
+/// dropped connection so a reconnect can resume where it left off.
+struct SessionState {
+    /// Replies that `handle_client` tried to send but the socket had
+    /// already gone away, in the order they were produced.
+    pending: Vec<Reply>,
+    last_seen: Instant,
+}
+
+/// Tracks sessions by [`SessionId`], guarded by a single `Mutex` alongside
+/// the spreadsheet's own locking style. Idle sessions are dropped by
+/// [`SessionTable::sweep_expired`] after `idle_timeout`.
+pub struct SessionTable {
+    sessions: Mutex<HashMap<SessionId, SessionState>>,
+    idle_timeout: Duration,
+}
+
+impl SessionTable {
+    pub fn new(idle_timeout: Duration) -> Self {
+        SessionTable {
+            sessions: Mutex::new(HashMap::new()),
+            idle_timeout,
+        }
+    }
+
+    /// Attaches to `id`, creating it if it doesn't exist yet, and returns
+    /// (and clears) any replies buffered while the client was disconnected.
+    pub fn attach(&self, id: &str) -> Vec<Reply> {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let state = sessions.entry(id.to_string()).or_insert_with(|| SessionState {
+            pending: Vec::new(),
+            last_seen: Instant::now(),
+        });
+
+        state.last_seen = Instant::now();
+        std::mem::take(&mut state.pending)
+    }
+
+    /// Records that `id` is still active, without touching its buffer.
+    pub fn touch(&self, id: &str) {
+        let mut sessions = self.sessions.lock().unwrap();
+        if let Some(state) = sessions.get_mut(id) {
+            state.last_seen = Instant::now();
+        }
+    }
+
+    /// Buffers `reply` for `id` so it can be flushed on the next [`attach`].
+    ///
+    /// [`attach`]: SessionTable::attach
+    pub fn buffer(&self, id: &str, reply: Reply) {
+        let mut sessions = self.sessions.lock().unwrap();
+
+        let state = sessions.entry(id.to_string()).or_insert_with(|| SessionState {
+            pending: Vec::new(),
+            last_seen: Instant::now(),
+        });
+
+        state.pending.push(reply);
+    }
+
+    /// Drops every session that hasn't been touched within `idle_timeout`.
+    pub fn sweep_expired(&self) {
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, state| state.last_seen.elapsed() < self.idle_timeout);
+    }
+}
+
+/// A client opens a session with a leading `session` / `session <id>`
+/// message, before sending any `Get`/`Set` commands. Returns the id to use
+/// (the one supplied, or a freshly generated one) alongside whether it was
+/// newly minted here (in which case the caller should report it back, since
+/// there is no other channel to hand the id to the client).
+pub fn parse_handshake(message: &str) -> Option<(SessionId, bool)> {
+    let mut parts = message.trim().splitn(2, char::is_whitespace);
+
+    if !parts.next()?.eq_ignore_ascii_case("session") {
+        return None;
+    }
+
+    match parts.next().map(str::trim).filter(|id| !id.is_empty()) {
+        Some(id) => Some((id.to_string(), false)),
+        None => Some((generate_session_id(), true)),
+    }
+}
+
+/// Wraps a freshly issued session id in a `Reply` so it can be sent back to
+/// the client over the existing `Reply` channel.
+pub fn issued_session_reply(id: &str) -> Reply {
+    Reply::Value("session".to_string(), CellValue::String(id.to_string()))
+}
+
+fn generate_session_id() -> String {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+    format!("session-{}", NEXT_ID.fetch_add(1, Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_supplied_session_id() {
+        let (id, newly_issued) = parse_handshake("session abc-123").unwrap();
+        assert_eq!(id, "abc-123");
+        assert!(!newly_issued);
+    }
+
+    #[test]
+    fn mints_a_fresh_id_when_none_supplied() {
+        let (id, newly_issued) = parse_handshake("session").unwrap();
+        assert!(!id.is_empty());
+        assert!(newly_issued);
+    }
+
+    #[test]
+    fn rejects_messages_that_are_not_a_handshake() {
+        assert!(parse_handshake("set a1 1").is_none());
+    }
+}
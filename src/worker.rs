@@ -0,0 +1,293 @@
+// rsheet_lib imports
+use rsheet_lib::cell_expr::CellExpr;
+use rsheet_lib::cell_value::CellValue;
+
+// Standard lib imports
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::sync::{mpsc, Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+// Internal imports
+use crate::{handle_context, persist_cell, SharedSpreadsheet, Spreadsheet, TimedCellValue};
+
+/// A single recalculation job: recompute the dependents of `cell`. Cells
+/// with more direct dependents sort first out of the `BinaryHeap`, so hot
+/// chains (cells that fan out widely) are recomputed before cold leaves.
+#[derive(Debug)]
+struct Work {
+    priority: u64,
+    cell: String,
+}
+
+// `Eq`/`PartialEq` are defined on `priority` alone to stay consistent with
+// `Ord`/`PartialOrd` below (two jobs of equal priority are "equal" for
+// ordering purposes even if they target different cells).
+impl PartialEq for Work {
+    fn eq(&self, other: &Self) -> bool {
+        self.priority == other.priority
+    }
+}
+
+impl Eq for Work {}
+
+impl Ord for Work {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority)
+    }
+}
+
+impl PartialOrd for Work {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// A `BinaryHeap` of pending `Work`, guarded by a `Mutex` and signalled by a
+/// `Condvar` so idle workers block instead of spinning.
+struct WorkQueue {
+    heap: Mutex<BinaryHeap<Work>>,
+    condvar: Condvar,
+}
+
+impl WorkQueue {
+    fn new() -> Self {
+        WorkQueue {
+            heap: Mutex::new(BinaryHeap::new()),
+            condvar: Condvar::new(),
+        }
+    }
+
+    fn push(&self, work: Work) {
+        let mut heap = self.heap.lock().unwrap();
+        heap.push(work);
+        self.condvar.notify_one();
+    }
+
+    fn pop(&self) -> Work {
+        let mut heap = self.heap.lock().unwrap();
+
+        loop {
+            if let Some(work) = heap.pop() {
+                return work;
+            }
+            heap = self.condvar.wait(heap).unwrap();
+        }
+    }
+}
+
+/// A bounded pool of background threads that perform dependency-cascade
+/// recalculation off a shared priority queue, so a `Set` with a large
+/// cascade no longer stalls the client that made it, and recalculation work
+/// competes fairly across clients.
+pub struct WorkerPool {
+    queue: Arc<WorkQueue>,
+    _handles: Vec<JoinHandle<()>>,
+}
+
+impl WorkerPool {
+    /// Spawns `size` worker threads sharing `spreadsheet`. Each time a
+    /// worker picks up a job it publishes a `"recomputing <cell>"` message
+    /// on `status_tx`, for external observability.
+    pub fn new(size: usize, spreadsheet: SharedSpreadsheet, status_tx: mpsc::Sender<String>) -> Self {
+        let queue = Arc::new(WorkQueue::new());
+        let mut handles = Vec::with_capacity(size);
+
+        for _ in 0..size {
+            let queue = Arc::clone(&queue);
+            let spreadsheet = Arc::clone(&spreadsheet);
+            let status_tx = status_tx.clone();
+
+            handles.push(thread::spawn(move || loop {
+                let work = queue.pop();
+                let _ = status_tx.send(format!("recomputing {}", work.cell));
+                recompute_cascade(&spreadsheet, &work.cell);
+            }));
+        }
+
+        WorkerPool {
+            queue,
+            _handles: handles,
+        }
+    }
+
+    /// Enqueues `cell` for recalculation, prioritised by how many cells
+    /// directly depend on it.
+    pub fn enqueue(&self, spreadsheet: &SharedSpreadsheet, cell: String) {
+        let priority = priority_of(spreadsheet, &cell);
+        self.queue.push(Work { priority, cell });
+    }
+}
+
+fn priority_of(spreadsheet: &SharedSpreadsheet, cell: &str) -> u64 {
+    let spreadsheet = spreadsheet.lock().unwrap();
+    spreadsheet
+        .dependencies
+        .get(cell)
+        .map(|dependents| dependents.len() as u64)
+        .unwrap_or(0)
+}
+
+/// Recomputes every cell transitively affected by a change to `cell`,
+/// visiting each one exactly once, only after all of its own inputs have
+/// already settled. This is a Kahn-style topological pass restricted to the
+/// subgraph reachable from `cell`, which avoids the stale reads a plain
+/// stack/queue walk can produce on a diamond (e.g. D depends on B and C,
+/// both depending on A: a naive walk can recompute D off a not-yet-updated
+/// B or C, then recompute it again).
+///
+/// The lock is reacquired per node rather than held for the whole cascade:
+/// a large cascade can take a while, and holding the one spreadsheet mutex
+/// throughout would serialize every other client's Get/Set behind it,
+/// defeating the point of spreading recalculation across `RECALC_WORKERS`.
+fn recompute_cascade(spreadsheet: &SharedSpreadsheet, cell: &str) {
+    let (mut ready, mut in_degree) = {
+        let locked = spreadsheet.lock().unwrap();
+
+        // Forward BFS from `cell` collects the full affected set.
+        let mut affected: HashSet<String> = HashSet::new();
+        let mut frontier: VecDeque<String> = VecDeque::new();
+        frontier.push_back(cell.to_string());
+
+        while let Some(current) = frontier.pop_front() {
+            if let Some(dependents) = locked.dependencies.get(&current) {
+                for dependent in dependents {
+                    if affected.insert(dependent.clone()) {
+                        frontier.push_back(dependent.clone());
+                    }
+                }
+            }
+        }
+
+        if affected.is_empty() {
+            return;
+        }
+
+        // In-degree of each affected node, counting only edges whose source is
+        // another affected node. `cell` itself is the root of the cascade, not
+        // a node we recompute, so its outgoing edges must NOT be counted here —
+        // otherwise every direct dependent of `cell` would start at in-degree 1
+        // with nothing left to ever decrement it, and `ready` would stay empty.
+        let mut in_degree: HashMap<String, usize> =
+            affected.iter().map(|node| (node.clone(), 0)).collect();
+
+        for source in &affected {
+            if let Some(dependents) = locked.dependencies.get(source) {
+                for dependent in dependents {
+                    if let Some(count) = in_degree.get_mut(dependent) {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+
+        let ready: VecDeque<String> = in_degree
+            .iter()
+            .filter(|(_, &count)| count == 0)
+            .map(|(node, _)| node.clone())
+            .collect();
+
+        (ready, in_degree)
+    };
+
+    while let Some(node) = ready.pop_front() {
+        // Each node is read, evaluated and written back under its own lock
+        // acquisition, so other clients get a chance to run between steps.
+        let mut locked = spreadsheet.lock().unwrap();
+
+        let original_expr_str = match locked.cells.get(&node).and_then(|c| c.expression.clone()) {
+            Some(expr) => expr,
+            None => {
+                // Still settled as far as the cascade is concerned: its
+                // dependents must not be left waiting on an in-degree that
+                // will never reach zero.
+                eprintln!("dependent {} has no valid expression to evaluate", node);
+                propagate_ready(&locked, &node, &mut in_degree, &mut ready);
+                continue;
+            }
+        };
+
+        let new_cell_expr = CellExpr::new(&original_expr_str);
+        let context = handle_context(&new_cell_expr, &locked.cells);
+
+        let new_value = match new_cell_expr.evaluate(&context) {
+            Ok(value) => value,
+            Err(_) => CellValue::Error("evaluation failed".to_string()),
+        };
+
+        locked.cells.insert(
+            node.clone(),
+            TimedCellValue {
+                value: new_value.clone(),
+                expression: Some(original_expr_str.clone()),
+                timestamp: Instant::now(),
+            },
+        );
+        persist_cell(&locked.db, &node, &new_value, Some(&original_expr_str));
+
+        propagate_ready(&locked, &node, &mut in_degree, &mut ready);
+    }
+}
+
+/// Decrements the in-degree of `node`'s dependents now that `node` has
+/// settled (whether or not it was actually recomputed), queuing any that
+/// reach zero. Every node removed from `ready` must call this exactly once,
+/// or its successors are left stranded at a permanently nonzero in-degree.
+fn propagate_ready(
+    spreadsheet: &Spreadsheet,
+    node: &str,
+    in_degree: &mut HashMap<String, usize>,
+    ready: &mut VecDeque<String>,
+) {
+    if let Some(dependents) = spreadsheet.dependencies.get(node).cloned() {
+        for dependent in dependents {
+            if let Some(count) = in_degree.get_mut(&dependent) {
+                *count -= 1;
+                if *count == 0 {
+                    ready.push_back(dependent);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{persistence, CellGrid, DependencyGraph};
+
+    fn cell(expr: &str, value: CellValue) -> TimedCellValue {
+        TimedCellValue {
+            value,
+            expression: Some(expr.to_string()),
+            timestamp: Instant::now(),
+        }
+    }
+
+    #[test]
+    fn recomputes_a_diamond_in_topological_order() {
+        // a1 feeds b1 and c1, both of which feed d1 = b1 + c1.
+        let mut cells: CellGrid = HashMap::new();
+        cells.insert("a1".to_string(), cell("5", CellValue::Int(5)));
+        cells.insert("b1".to_string(), cell("a1", CellValue::Int(5)));
+        cells.insert("c1".to_string(), cell("a1", CellValue::Int(5)));
+        cells.insert("d1".to_string(), cell("b1+c1", CellValue::Int(10)));
+
+        let mut dependencies: DependencyGraph = HashMap::new();
+        dependencies.insert("a1".to_string(), vec!["b1".to_string(), "c1".to_string()]);
+        dependencies.insert("b1".to_string(), vec!["d1".to_string()]);
+        dependencies.insert("c1".to_string(), vec!["d1".to_string()]);
+
+        let db = persistence::open_db(":memory:").unwrap();
+        let spreadsheet = Arc::new(Mutex::new(Spreadsheet { cells, dependencies, db }));
+
+        spreadsheet.lock().unwrap().cells.insert("a1".to_string(), cell("7", CellValue::Int(7)));
+        recompute_cascade(&spreadsheet, "a1");
+
+        let spreadsheet = spreadsheet.lock().unwrap();
+        assert_eq!(spreadsheet.cells["b1"].value, CellValue::Int(7));
+        assert_eq!(spreadsheet.cells["c1"].value, CellValue::Int(7));
+        assert_eq!(spreadsheet.cells["d1"].value, CellValue::Int(14));
+    }
+}